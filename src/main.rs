@@ -3,10 +3,13 @@ use chrono::{DateTime, FixedOffset, Local, NaiveDateTime};
 use git2::{Oid, Repository, Signature, Time};
 use globset::GlobMatcher;
 use lazy_static::lazy_static;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::cell::RefCell;
 use std::collections::{BTreeMap, btree_map};
-use std::collections::HashSet;
-use std::process::{Command, Stdio};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::str::FromStr;
 use structopt::StructOpt;
 
 use ansi_term::Colour;
@@ -23,6 +26,9 @@ pub enum Error {
 
     #[error("Io error: {0}")]
     Io(std::io::Error),
+
+    #[error("JSON serialization error; {0}")]
+    Json(serde_json::Error),
 }
 
 #[derive(StructOpt, Clone)]
@@ -53,6 +59,104 @@ struct Args {
     /// Show all the variants of commits having the same commit subject line
     #[structopt(name = "variants", long, short = "v")]
     variants: bool,
+
+    /// Output format: `human` for the ANSI grid, `json` for a machine-readable array
+    #[structopt(name = "format", long, default_value = "human")]
+    format: OutputFormat,
+
+    /// Print estimated working hours per author instead of the containment grid
+    #[structopt(name = "estimate-hours", long)]
+    estimate_hours: bool,
+
+    /// Commits no more than this many minutes apart are assumed to belong to the same session
+    #[structopt(name = "max-commit-diff", long, default_value = "120")]
+    max_commit_diff: u64,
+
+    /// Minutes of work assumed to precede the first commit of a session
+    #[structopt(name = "first-commit-addition", long, default_value = "120")]
+    first_commit_addition: u64,
+
+    /// Annotate each commit with its nearest reachable tag, `git describe`-style
+    #[structopt(name = "describe", long)]
+    describe: bool,
+
+    /// Group variants by `subject` or by a commit trailer, e.g. `trailer:Change-Id`
+    #[structopt(name = "group-by", long, default_value = "subject")]
+    group_by: GroupBy,
+
+    /// Show each branch's ahead/behind commit counts against this base ref
+    #[structopt(name = "base", long)]
+    base: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+enum GroupBy {
+    Subject,
+    Trailer(String),
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "subject" {
+            Ok(GroupBy::Subject)
+        } else if let Some(key) = s.strip_prefix("trailer:") {
+            if key.is_empty() {
+                Err("--group-by trailer key must not be empty".to_owned())
+            } else {
+                Ok(GroupBy::Trailer(key.to_owned()))
+            }
+        } else {
+            Err(format!(
+                "unknown --group-by '{}', expected 'subject' or 'trailer:<KEY>'",
+                s
+            ))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum OutputFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!(
+                "unknown format '{}', expected one of: human, json",
+                other
+            )),
+        }
+    }
+}
+
+/// A single commit as reported by the containment matrix, ready to be
+/// serialized without any ANSI formatting applied.
+#[derive(Serialize)]
+struct JsonCommit {
+    id: String,
+    author: String,
+    subject: String,
+    time: i64,
+    contained_in: Vec<String>,
+    variants: Vec<JsonVariant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    describe: Option<String>,
+}
+
+/// One (commit, branch-set) pair belonging to a `JsonCommit`'s `--variants` group.
+#[derive(Serialize)]
+struct JsonVariant {
+    id: String,
+    branches: Vec<String>,
+    diff_id: String,
 }
 
 fn sig_matches(sig: &Signature, arg: &Option<String>) -> bool {
@@ -65,6 +169,35 @@ fn sig_matches(sig: &Signature, arg: &Option<String>) -> bool {
     }
 }
 
+/// Whether `line` looks like a trailer line, i.e. `Key: value` with a
+/// token-shaped key (letters, digits and `-`).
+fn is_trailer_line(line: &str) -> bool {
+    match line.split_once(':') {
+        Some((k, _)) => !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '-'),
+        None => false,
+    }
+}
+
+/// Looks up `key` in a commit message's trailer block: the last paragraph,
+/// if every one of its lines is a `Key: value` trailer. Returns `None` if
+/// the message has no such trailer block, or the key isn't in it.
+fn find_trailer<'a>(message: &'a str, key: &str) -> Option<&'a str> {
+    let last_paragraph = message.trim_end().rsplit("\n\n").next()?;
+    let lines: Vec<&str> = last_paragraph.lines().collect();
+    if lines.is_empty() || !lines.iter().all(|line| is_trailer_line(line)) {
+        return None;
+    }
+
+    lines.iter().rev().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        if k.trim() == key {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
 fn print_time(time: &Time, index: usize) {
     let dt = DateTime::<Local>::from_utc(
         NaiveDateTime::from_timestamp_opt(time.seconds(), 0).expect("invalid timstamp"),
@@ -83,9 +216,121 @@ fn print_time(time: &Time, index: usize) {
     );
 }
 
+/// Computes a patch identity for a non-merge commit: the unified diff between
+/// its parent tree and its own tree, normalized so that cherry-picks and
+/// rebases onto a different base hash identically (hunk headers collapsed to
+/// a bare `@@`, and the `diff --git`/`index `/`---`/`+++` header lines, which
+/// only describe paths and blob ids rather than the actual change, dropped).
+fn compute_diff_id(repo: &Repository, oid: Oid) -> String {
+    let commit = repo
+        .find_commit(oid)
+        .expect("commit must exist for diff-id computation");
+    let tree = commit.tree().expect("commit must have a tree");
+    let parent_tree = commit.parents().next().and_then(|p| p.tree().ok());
+
+    let diff = repo
+        .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+        .expect("diff_tree_to_tree must succeed");
+
+    let mut hasher = Sha1::new();
+    diff.print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+        match line.origin() {
+            'F' => {}
+            'H' => hasher.update(b"@@\n"),
+            origin => {
+                hasher.update(&[origin as u8]);
+                hasher.update(line.content());
+            }
+        }
+        true
+    })
+    .expect("diff print must succeed");
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Memoizes [`compute_diff_id`] by [`Oid`] so a commit reached from several
+/// branches is only diffed and hashed once.
+struct DiffIdCache {
+    cache: RefCell<HashMap<Oid, Rc<str>>>,
+}
+
+impl DiffIdCache {
+    fn new() -> Self {
+        DiffIdCache {
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, repo: &Repository, oid: Oid) -> Rc<str> {
+        if let Some(existing) = self.cache.borrow().get(&oid) {
+            return existing.clone();
+        }
+
+        let computed: Rc<str> = Rc::from(compute_diff_id(repo, oid));
+        self.cache.borrow_mut().insert(oid, computed.clone());
+        computed
+    }
+}
+
+/// How many commits away from a commit its nearest reachable tag sits, caps
+/// how far [`describe_commit`] is willing to walk before giving up.
+const DESCRIBE_MAX_DEPTH: usize = 1000;
+
+/// The `git describe`-style annotation for a commit: the nearest reachable
+/// tag together with its depth. [`describe_commit`] has no `Outcome` when no
+/// tag was found within [`DESCRIBE_MAX_DEPTH`] commits.
+struct Outcome {
+    name: Rc<str>,
+    depth: usize,
+    short_id: String,
+}
+
+/// Walks the ancestry of `oid` in date order, counting commits until the
+/// first one present in `tags`, and renders the result `git describe`-style
+/// as `name-<depth>-g<12hex>`, falling back to the bare short hash when no
+/// tag is reachable within [`DESCRIBE_MAX_DEPTH`] commits.
+fn describe_commit(repo: &Repository, tags: &HashMap<Oid, Rc<str>>, oid: Oid) -> String {
+    let short_id = oid.to_string()[..12].to_owned();
+
+    let outcome = (|| -> Result<Option<Outcome>, git2::Error> {
+        let mut revwalk = repo.revwalk()?;
+        revwalk.push(oid)?;
+        revwalk.set_sorting(git2::Sort::TIME)?;
+
+        for (depth, candidate) in revwalk.enumerate() {
+            if depth >= DESCRIBE_MAX_DEPTH {
+                break;
+            }
+            let candidate = candidate?;
+            if let Some(name) = tags.get(&candidate) {
+                return Ok(Some(Outcome {
+                    name: name.clone(),
+                    depth,
+                    short_id: short_id.clone(),
+                }));
+            }
+        }
+
+        Ok(None)
+    })()
+    .unwrap_or(None);
+
+    match outcome {
+        Some(Outcome {
+            name,
+            depth,
+            short_id,
+        }) => format!("{}-{}-g{}", name, depth, short_id),
+        None => short_id,
+    }
+}
+
 fn print_commit(
     idx: usize,
-    _repo: &Repository,
+    repo: &Repository,
+    diff_id_cache: &DiffIdCache,
+    tags: &Option<HashMap<Oid, Rc<str>>>,
     time: &Time,
     msg: &String,
     id_revs: &Vec<(&Oid, &HashSet<Rc<String>>)>,
@@ -109,14 +354,7 @@ fn print_commit(
     for (oid, c_revs) in id_revs {
         print_time(&time, idx);
 
-        let diff_id = String::from_utf8(
-            Command::new("sh")
-            .arg("-c")
-            .arg(&format!("git show {oid} --format= | cat | sed 's/^@@.*/@@/g' | sed 's/^index.*//' | sha1sum -"))
-            .stdout(Stdio::piped())
-            .output()
-            .expect("failed executing 'git show'").stdout)
-            .expect("utf-8 conversion");
+        let diff_id = diff_id_cache.get(repo, **oid);
 
         for (i, item) in branches.iter().enumerate() {
             let revs = if variants {
@@ -141,6 +379,12 @@ fn print_commit(
                 print!(" {}", "        ");
             }
         }
+        if let Some(tags) = tags {
+            print!(
+                " {}",
+                RGB(150, 150, 150).paint(describe_commit(repo, tags, **oid))
+            );
+        }
         print!(" {}", White.bold().paint(msg));
 
         println!();
@@ -151,12 +395,66 @@ fn print_commit(
     }
 }
 
+/// Prints a git-hours-style estimate of time invested per author: commits
+/// within `max_commit_diff` minutes of each other are assumed to belong to
+/// the same working session, and each session (including a lone commit) is
+/// credited `first_commit_addition` minutes of work leading up to it.
+fn print_estimated_hours(commits: &[(String, i64)], max_commit_diff: u64, first_commit_addition: u64) {
+    let max_commit_diff_secs = (max_commit_diff * 60) as i64;
+    let first_commit_addition_secs = (first_commit_addition * 60) as i64;
+
+    let mut by_author: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+    for (author, time) in commits {
+        by_author
+            .entry(author.as_str())
+            .or_insert_with(Vec::new)
+            .push(*time);
+    }
+
+    let mut total_seconds = 0i64;
+    let mut total_commits = 0usize;
+
+    for (author, mut times) in by_author {
+        times.sort_unstable();
+
+        let mut seconds = first_commit_addition_secs;
+        for window in times.windows(2) {
+            let gap = window[1] - window[0];
+            if gap <= max_commit_diff_secs {
+                seconds += gap;
+            } else {
+                seconds += first_commit_addition_secs;
+            }
+        }
+
+        total_seconds += seconds;
+        total_commits += times.len();
+
+        println!(
+            "{}: {:.1} hours ({} commits)",
+            author,
+            seconds as f64 / 3600.0,
+            times.len()
+        );
+    }
+
+    println!();
+    println!(
+        "Total: {:.1} hours, {} commits",
+        total_seconds as f64 / 3600.0,
+        total_commits
+    );
+}
+
 struct Printer<'a> {
     args: Args,
     repo: git2::Repository,
     colors: Vec<Colour>,
     branches: Vec<Rc<String>>,
     v: Vec<(Time, String, Vec<(&'a Oid, &'a HashSet<Rc<String>>)>)>,
+    diff_id_cache: DiffIdCache,
+    tags: Option<HashMap<Oid, Rc<str>>>,
+    ahead_behind: Option<HashMap<String, (usize, usize)>>,
 }
 
 impl<'a> Printer<'a> {
@@ -166,6 +464,8 @@ impl<'a> Printer<'a> {
                 print_commit(
                     idx,
                     &self.repo,
+                    &self.diff_id_cache,
+                    &self.tags,
                     &timestamp,
                     &msg,
                     &id_revs,
@@ -180,6 +480,8 @@ impl<'a> Printer<'a> {
                 print_commit(
                     idx,
                     &self.repo,
+                    &self.diff_id_cache,
+                    &self.tags,
                     &timestamp,
                     &msg,
                     &id_revs,
@@ -211,9 +513,15 @@ impl<'a> Printer<'a> {
         for c in 0..i {
             print!("{}", self.colors[c % self.colors.len()].paint(format!("│")));
         }
+
+        let suffix = match self.ahead_behind.as_ref().and_then(|m| m.get(name)) {
+            Some((ahead, behind)) => format!(" (↑{} ↓{})", ahead, behind),
+            None => String::new(),
+        };
+
         println!(
             "{}",
-            self.colors[i % self.colors.len()].paint(format!("{}", name))
+            self.colors[i % self.colors.len()].paint(format!("{}{}", name, suffix))
         );
     }
 
@@ -229,7 +537,67 @@ impl<'a> Printer<'a> {
         println!("");
     }
 
+    fn to_json_commits(&self) -> Result<Vec<JsonCommit>, Error> {
+        let mut out = Vec::with_capacity(self.v.len());
+
+        for (time, msg, id_revs) in &self.v {
+            if let Some(highlight) = &self.args.search {
+                if !msg.contains(highlight) {
+                    continue;
+                }
+            }
+
+            let mut contained_in = HashSet::new();
+            for (_, c_revs) in id_revs {
+                contained_in = contained_in.union(c_revs).cloned().collect();
+            }
+
+            let variants = id_revs
+                .iter()
+                .map(|(oid, c_revs)| JsonVariant {
+                    id: oid.to_string(),
+                    branches: c_revs.iter().map(|b| (**b).clone()).collect(),
+                    diff_id: self.diff_id_cache.get(&self.repo, **oid).to_string(),
+                })
+                .collect();
+
+            let (id, author, describe) = match id_revs.first() {
+                Some((oid, _)) => {
+                    let commit = self.repo.find_commit(**oid)?;
+                    let author = commit.author().name().unwrap_or("").to_owned();
+                    let describe = self
+                        .tags
+                        .as_ref()
+                        .map(|tags| describe_commit(&self.repo, tags, **oid));
+                    (oid.to_string(), author, describe)
+                }
+                None => continue,
+            };
+
+            out.push(JsonCommit {
+                id,
+                author,
+                subject: msg.clone(),
+                time: time.seconds(),
+                contained_in: contained_in.iter().map(|b| (**b).clone()).collect(),
+                variants,
+                describe,
+            });
+        }
+
+        Ok(out)
+    }
+
     fn print(&self) -> Result<(), Error> {
+        if self.args.format == OutputFormat::Json {
+            let commits = self.to_json_commits()?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&commits).map_err(Error::Json)?
+            );
+            return Ok(());
+        }
+
         if self.args.reverse {
             self.print_branches();
             self.print_sep();
@@ -305,6 +673,7 @@ fn main() -> anyhow::Result<()> {
     // Which commits OIDs in which branches
     let mut mapoid_to_branches = BTreeMap::new();
     let mut found_branches = BTreeMap::new();
+    let mut branch_oids: HashMap<String, Oid> = HashMap::new();
 
     let mut branches = vec![];
     for refe in repo.references()? {
@@ -341,7 +710,9 @@ fn main() -> anyhow::Result<()> {
             found_branches.insert(st.to_owned().clone(), (idx, show_if_empty));
 
             let name = Rc::new(format!("{}", st));
-            branches.push((name.to_owned(), revspec.from().unwrap().id()));
+            let oid = revspec.from().unwrap().id();
+            branch_oids.insert((*name).clone(), oid);
+            branches.push((name.to_owned(), oid));
         }
     }
 
@@ -366,6 +737,7 @@ fn main() -> anyhow::Result<()> {
                         let oid = revspec.from().unwrap().id();
 
                         found_branches.insert(st.to_owned().clone(), (idx, branch_info.show_if_empty));
+                        branch_oids.insert(name.to_owned(), oid);
                         branches.push((Rc::new(name.to_owned()), oid));
                     }
                 },
@@ -410,6 +782,22 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    if args.estimate_hours {
+        let mut commits = Vec::new();
+        for id in mapoid_to_branches.keys() {
+            let commit = repo.find_commit(*id)?;
+            if !sig_matches(&commit.author(), &author) {
+                continue;
+            }
+
+            let name = commit.author().name().unwrap_or("<unknown>").to_owned();
+            commits.push((name, commit.committer().when().seconds()));
+        }
+
+        print_estimated_hours(&commits, args.max_commit_diff, args.first_commit_addition);
+        return Ok(());
+    }
+
     // Which commit messages map to what OIDs, skipping merges
     let mut msg_map = BTreeMap::new();
     for (id, revs) in &mapoid_to_branches {
@@ -430,19 +818,26 @@ fn main() -> anyhow::Result<()> {
             continue;
         }
 
-        for msg in String::from_utf8_lossy(commit.message_bytes()).lines() {
-            let item = match msg_map.entry(String::from(msg)) {
-                btree_map::Entry::Vacant(v) => v.insert((committer.when(), Vec::new())),
-                btree_map::Entry::Occupied(o) => o.into_mut(),
-            };
-            item.1.push((id, revs));
-            break;
-        }
+        let full_message = String::from_utf8_lossy(commit.message_bytes()).into_owned();
+        let subject = full_message.lines().next().unwrap_or("").to_owned();
+
+        let key = match &args.group_by {
+            GroupBy::Subject => subject.clone(),
+            GroupBy::Trailer(trailer_key) => find_trailer(&full_message, trailer_key)
+                .map(|v| v.to_owned())
+                .unwrap_or_else(|| subject.clone()),
+        };
+
+        let item = match msg_map.entry(key) {
+            btree_map::Entry::Vacant(v) => v.insert((committer.when(), subject, Vec::new())),
+            btree_map::Entry::Occupied(o) => o.into_mut(),
+        };
+        item.2.push((id, revs));
     }
 
     let mut v = vec![];
-    for (msg, (when, id_revs)) in msg_map {
-        v.push((when, msg, id_revs));
+    for (_key, (when, subject, id_revs)) in msg_map {
+        v.push((when, subject, id_revs));
     }
 
     v.sort_by(|y, x| y.0.cmp(&x.0));
@@ -466,6 +861,20 @@ fn main() -> anyhow::Result<()> {
     branches.sort();
     let branches: Vec<_> = branches.into_iter().map(|x| x.1).collect();
 
+    let ahead_behind = if let Some(base) = &args.base {
+        let base_oid = repo.revparse(base)?.from().unwrap().id();
+        let mut counts = HashMap::new();
+        for name in &branches {
+            if let Some(oid) = branch_oids.get(&**name) {
+                let (ahead, behind) = repo.graph_ahead_behind(*oid, base_oid)?;
+                counts.insert((**name).clone(), (ahead, behind));
+            }
+        }
+        Some(counts)
+    } else {
+        None
+    };
+
     let mut colors = vec![];
     let m = 2;
     let n = 100;
@@ -478,12 +887,30 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
+    let tags = if args.describe {
+        let mut tag_map = HashMap::new();
+        for name in repo.tag_names(None)?.iter().flatten() {
+            let refname = format!("refs/tags/{}", name);
+            if let Ok(obj) = repo.revparse_single(&refname) {
+                if let Ok(commit) = obj.peel_to_commit() {
+                    tag_map.entry(commit.id()).or_insert_with(|| Rc::from(name));
+                }
+            }
+        }
+        Some(tag_map)
+    } else {
+        None
+    };
+
     Printer {
         args,
         repo,
         colors,
         branches,
         v,
+        diff_id_cache: DiffIdCache::new(),
+        tags,
+        ahead_behind,
     }
     .print()?;
 